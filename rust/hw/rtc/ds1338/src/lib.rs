@@ -3,21 +3,99 @@
 
 use bql::BqlRefCell;
 use hwcore::{
-    DeviceImpl, I2CEvent, I2CResult, I2CSlave, I2CSlaveClass, I2CSlaveImpl, ResetType,
-    ResettablePhasesImpl,
+    DeviceImpl, DeviceState, I2CEvent, I2CResult, I2CSlave, I2CSlaveClass, I2CSlaveImpl,
+    Property, ResetType, ResettablePhasesImpl,
 };
 use migration::{
     impl_vmstate_struct, vmstate_fields, VMStateDescription, VMStateDescriptionBuilder,
 };
 use qom::{qom_isa, ObjectImpl, ParentField};
+use std::cell::Cell;
 use std::mem::MaybeUninit;
-use system::bindings::{qemu_get_timedate, qemu_timedate_diff, tm};
+use std::os::raw::c_void;
+use system::bindings::{
+    blk_getlength, blk_pread, blk_pwrite, ptimer_init, ptimer_run, ptimer_set_freq,
+    ptimer_set_limit, ptimer_state, ptimer_stop, ptimer_transaction_begin,
+    ptimer_transaction_commit, qdev_init_gpio_out, qdev_init_gpio_out_named, qemu_bh_new,
+    qemu_get_timedate, qemu_irq, qemu_set_irq, qemu_timedate_diff, tm, BlockBackend,
+    PTIMER_POLICY_DEFAULT,
+};
 
+/// `ds1338` stays the default-selected member of the family for backwards
+/// compatibility; `variant` (see [`RtcVariant`]) picks DS1307/DS1338/DS3231
+/// register semantics on the same QOM type.
 pub const TYPE_DS1338: &::std::ffi::CStr = c"ds1338";
 const NVRAM_SIZE: usize = 64;
 const HOURS_12: u8 = 0x40;
 const HOURS_PM: u8 = 0x20;
 const CTRL_OSF: u8 = 0x20;
+const CTRL_OUT: u8 = 0x80;
+const CTRL_SQWE: u8 = 0x10;
+const CTRL_RS_MASK: u8 = 0x03;
+/// On-disk layout of the persisted state: offset (i64 LE) + wday_offset + nvram.
+const BLK_IMAGE_SIZE: usize = 8 + 1 + NVRAM_SIZE;
+
+/// DS3231/DS1337 register addresses (DS1307/DS1338 only use `0..7` plus a
+/// single control register at `7`).
+const DS3231_ALARM1_ADDR: i32 = 0x07;
+const DS3231_ALARM2_ADDR: i32 = 0x0b;
+const DS3231_CTRL_ADDR: i32 = 0x0e;
+const DS3231_STATUS_ADDR: i32 = 0x0f;
+const ALARM_MASK_BIT: u8 = 0x80;
+const STATUS_A1F: u8 = 0x01;
+const STATUS_A2F: u8 = 0x02;
+const STATUS_OSF: u8 = 0x80;
+const DS3231_CTRL_A1IE: u8 = 0x01;
+const DS3231_CTRL_A2IE: u8 = 0x02;
+
+/// Selects which member of the DS1307/DS1338/DS3231 I2C RTC family this
+/// instance emulates; set via the `variant` property, defaults to DS1338.
+///
+/// Only this property-based selection is implemented: there are no separate
+/// `TYPE_DS1307`/`TYPE_DS3231` QOM type names, so board code that expects to
+/// instantiate those chips by their own type string (rather than
+/// `-device ds1338,variant=N`) won't find them. Follow-up, if a board needs
+/// it: register thin QOM subtypes whose `instance_init` just sets
+/// `variant_prop` and otherwise reuse `DS1338State` as-is.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RtcVariant {
+    Ds1307 = 0,
+    #[default]
+    Ds1338 = 1,
+    /// Also covers DS1337, which shares the same alarm/control layout.
+    Ds3231 = 2,
+}
+
+impl RtcVariant {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => RtcVariant::Ds1307,
+            2 => RtcVariant::Ds3231,
+            _ => RtcVariant::Ds1338,
+        }
+    }
+
+    /// Address of the (single) control register, except on DS3231 where
+    /// control and status are split across two registers.
+    fn control_addr(self) -> i32 {
+        match self {
+            RtcVariant::Ds3231 => DS3231_CTRL_ADDR,
+            RtcVariant::Ds1307 | RtcVariant::Ds1338 => 7,
+        }
+    }
+
+    fn status_addr(self) -> Option<i32> {
+        match self {
+            RtcVariant::Ds3231 => Some(DS3231_STATUS_ADDR),
+            RtcVariant::Ds1307 | RtcVariant::Ds1338 => None,
+        }
+    }
+
+    fn has_alarms(self) -> bool {
+        self == RtcVariant::Ds3231
+    }
+}
 
 fn to_bcd(x: u8) -> u8 {
     (x / 10) * 16 + (x % 10)
@@ -35,6 +113,9 @@ pub struct DS1338Inner {
     pub nvram: [u8; NVRAM_SIZE],
     pub ptr: i32,
     pub addr_byte: bool,
+    /// Fixed for the lifetime of the device by the `variant` property; not
+    /// itself migrated since both ends of a migration are configured alike.
+    pub variant: RtcVariant,
 }
 
 impl Default for DS1338Inner {
@@ -45,6 +126,7 @@ impl Default for DS1338Inner {
             nvram: [0; NVRAM_SIZE],
             ptr: 0,
             addr_byte: false,
+            variant: RtcVariant::default(),
         }
     }
 }
@@ -70,6 +152,23 @@ impl_vmstate_struct!(
 pub struct DS1338State {
     pub parent_obj: ParentField<I2CSlave>,
     pub inner: BqlRefCell<DS1338Inner>,
+    /// Square-wave/clock output (SQW) pin, driven from control register bits.
+    pub sqw: Cell<qemu_irq>,
+    /// Periodic timer toggling `sqw` every half period of the frequency
+    /// selected by RS1:RS0.
+    pub sqw_timer: Cell<*mut ptimer_state>,
+    pub sqw_level: Cell<bool>,
+    /// Optional `-drive` backend backing the clock offset and NVRAM, so both
+    /// survive a VM restart the way a battery-backed part would.
+    pub blk: Cell<*mut BlockBackend>,
+    /// Alarm interrupt output, only driven on variants with alarms (DS3231).
+    pub alarm_irq: Cell<qemu_irq>,
+    /// Once-a-second timer that compares the alarm registers against wall
+    /// clock time independent of I2C traffic, only run on variants with
+    /// alarms; otherwise left created but never armed.
+    pub alarm_timer: Cell<*mut ptimer_state>,
+    /// Raw `variant` property value; converted into `inner.variant` at realize.
+    pub variant_prop: Cell<u8>,
 }
 
 impl DS1338Inner {
@@ -100,9 +199,57 @@ impl DS1338Inner {
             self.nvram[4] = to_bcd(now.tm_mday as u8);
             self.nvram[5] = to_bcd((now.tm_mon + 1) as u8);
             self.nvram[6] = to_bcd((now.tm_year - 100) as u8);
+
+            if self.variant.has_alarms() {
+                self.update_alarm_flags(&now);
+            }
+        }
+    }
+
+    /// Compare the alarm registers against the current wall-clock time,
+    /// without touching the displayed clock registers. Called once a second
+    /// from `alarm_timer` so the alarm fires even while the guest isn't
+    /// doing I2C traffic (e.g. it's asleep, waiting to be woken up).
+    pub fn check_alarms(&mut self) {
+        if !self.variant.has_alarms() {
+            return;
+        }
+        unsafe {
+            let mut now = MaybeUninit::<tm>::uninit();
+            qemu_get_timedate(now.as_mut_ptr(), self.offset);
+            let now = now.assume_init();
+            self.update_alarm_flags(&now);
         }
     }
 
+    /// Compare the current time against the alarm registers and latch
+    /// A1F/A2F in the status register on a match. Alarm hour fields are
+    /// always compared in 24-hour BCD, unlike the 12/24-hour clock register.
+    fn update_alarm_flags(&mut self, now: &tm) {
+        let fields = [
+            to_bcd(now.tm_sec as u8),
+            to_bcd(now.tm_min as u8),
+            to_bcd(now.tm_hour as u8),
+            to_bcd(now.tm_mday as u8),
+        ];
+
+        if self.alarm_matches(DS3231_ALARM1_ADDR, &fields) {
+            self.nvram[DS3231_STATUS_ADDR as usize] |= STATUS_A1F;
+        }
+        if self.alarm_matches(DS3231_ALARM2_ADDR, &fields[1..]) {
+            self.nvram[DS3231_STATUS_ADDR as usize] |= STATUS_A2F;
+        }
+    }
+
+    /// A register's top bit (A1M1..A1M4/A2M2..A2M4) marks that field as
+    /// "don't care"; otherwise the BCD value must match.
+    fn alarm_matches(&self, base: i32, fields_bcd: &[u8]) -> bool {
+        fields_bcd.iter().enumerate().all(|(i, &want)| {
+            let reg = self.nvram[base as usize + i];
+            reg & ALARM_MASK_BIT != 0 || (reg & 0x7f) == want
+        })
+    }
+
     pub fn inc_regptr(&mut self) {
         self.ptr = (self.ptr + 1) & (NVRAM_SIZE as i32 - 1);
         if self.ptr == 0 {
@@ -166,15 +313,35 @@ impl DS1338Inner {
     }
 
     pub fn write_control_register(&mut self, data: u8) {
-        let mut data = data & 0xB3;
-        data = (data & !CTRL_OSF) | (data & self.nvram[self.ptr as usize] & CTRL_OSF);
+        let addr = self.ptr as usize;
+
+        self.nvram[addr] = match self.variant {
+            // DS3231's control register has its own, unrelated layout
+            // (EOSC/BBSQW/CONV/RS2/RS1/INTCN/A2IE/A1IE); take it as-is.
+            RtcVariant::Ds3231 => data,
+            RtcVariant::Ds1307 => data & 0xB3,
+            RtcVariant::Ds1338 => {
+                let data = data & 0xB3;
+                (data & !CTRL_OSF) | (data & self.nvram[addr] & CTRL_OSF)
+            }
+        };
+    }
 
-        self.nvram[self.ptr as usize] = data;
+    /// DS3231 status register: A1F/A2F/OSF are flags the guest can only
+    /// clear (by writing 0), never set, by writing through this register.
+    pub fn write_status_register(&mut self, data: u8) {
+        let addr = self.ptr as usize;
+        let flags = STATUS_OSF | STATUS_A2F | STATUS_A1F;
+        self.nvram[addr] = (data & !flags) | (self.nvram[addr] & data & flags);
     }
 
     pub fn write_nvram(&mut self, data: u8) {
         self.nvram[self.ptr as usize] = data;
     }
+
+    pub fn control_register(&self) -> u8 {
+        self.nvram[self.variant.control_addr() as usize]
+    }
 }
 
 qom_isa!(DS1338State: I2CSlave, hwcore::DeviceState, qom::Object);
@@ -190,6 +357,16 @@ impl ObjectImpl for DS1338State {
 }
 
 impl DeviceImpl for DS1338State {
+    const REALIZE: Option<fn(&Self)> = Some(Self::realize);
+    const PROPERTIES: &'static [Property] = &[
+        hwcore::define_prop_drive!(c"drive", DS1338State, blk),
+        hwcore::define_prop_uint8!(
+            c"variant",
+            DS1338State,
+            variant_prop,
+            RtcVariant::Ds1338 as u8
+        ),
+    ];
     const VMSTATE: Option<migration::VMStateDescription<Self>> = Some(VMSTATE_DS1338);
 }
 
@@ -216,41 +393,269 @@ impl DS1338State {
             return I2CResult::ACK;
         }
 
-        if inner.ptr < 7 {
+        let ptr = inner.ptr;
+        let variant = inner.variant;
+        let control_addr = variant.control_addr();
+        let old_sqw_bits = inner.control_register() & (CTRL_SQWE | CTRL_RS_MASK);
+        if ptr < 7 {
             inner.write_time_register(data);
-        } else if inner.ptr == 7 {
+        } else if ptr == control_addr {
             inner.write_control_register(data);
+        } else if Some(ptr) == variant.status_addr() {
+            inner.write_status_register(data);
         } else {
             inner.write_nvram(data);
         }
 
         inner.inc_regptr();
+        let new_sqw_bits = inner.control_register() & (CTRL_SQWE | CTRL_RS_MASK);
+        drop(inner);
+
+        if ptr == control_addr {
+            // Only restart the SQW ptimer when SQWE/RS actually changed; a
+            // guest read-modify-writing an unrelated control bit (e.g. OUT)
+            // must not glitch/reset the square wave's phase.
+            self.update_sqw(old_sqw_bits != new_sqw_bits);
+        }
+        self.flush_blk();
+        self.update_alarm_irq();
+
         I2CResult::ACK
     }
 
+    /// Reprogram the SQW timer/pin from the current control register.
+    ///
+    /// `reconfigure_timer` must be false right after a migration load: the
+    /// ptimer's running countdown and phase are already restored by
+    /// `VMSTATE_PTIMER`, so re-arming it from scratch here would throw that
+    /// phase away. It's only the static pin level (SQWE=0 case) and
+    /// `sqw_level` cache that still need resyncing in that case, since
+    /// neither is itself migrated.
+    fn update_sqw(&self, reconfigure_timer: bool) {
+        let inner = self.inner.borrow();
+        if inner.variant.has_alarms() {
+            // DS3231 multiplexes SQW/INT onto one pin via INTCN; only the
+            // alarm interrupt path is modeled for this variant.
+            return;
+        }
+        let ctrl = inner.control_register();
+        drop(inner);
+        let timer = self.sqw_timer.get();
+
+        if ctrl & CTRL_SQWE != 0 {
+            if reconfigure_timer {
+                let freq_hz: u32 = match ctrl & CTRL_RS_MASK {
+                    0 => 1,
+                    1 => 4096,
+                    2 => 8192,
+                    _ => 32768,
+                };
+                unsafe {
+                    ptimer_transaction_begin(timer);
+                    // Toggle twice per period to produce a square wave at freq_hz.
+                    ptimer_set_freq(timer, freq_hz * 2);
+                    ptimer_set_limit(timer, 1, 1);
+                    ptimer_run(timer, 0);
+                    ptimer_transaction_commit(timer);
+                }
+            }
+        } else {
+            if reconfigure_timer {
+                unsafe {
+                    ptimer_transaction_begin(timer);
+                    ptimer_stop(timer);
+                    ptimer_transaction_commit(timer);
+                }
+            }
+            self.sqw_level.set(ctrl & CTRL_OUT != 0);
+            unsafe { qemu_set_irq(self.sqw.get(), self.sqw_level.get() as i32) };
+        }
+    }
+
+    extern "C" fn sqw_tick_trampoline(opaque: *mut c_void) {
+        let state = unsafe { &*(opaque as *const Self) };
+        state.sqw_tick();
+    }
+
+    fn sqw_tick(&self) {
+        let level = !self.sqw_level.get();
+        self.sqw_level.set(level);
+        unsafe { qemu_set_irq(self.sqw.get(), level as i32) };
+    }
+
+    extern "C" fn alarm_tick_trampoline(opaque: *mut c_void) {
+        let state = unsafe { &*(opaque as *const Self) };
+        state.alarm_tick();
+    }
+
+    fn alarm_tick(&self) {
+        self.inner.borrow_mut().check_alarms();
+        self.update_alarm_irq();
+    }
+
+    /// Arm `alarm_timer` to re-check the alarm registers once a second,
+    /// regardless of I2C traffic. No-op on variants without alarms.
+    fn start_alarm_timer(&self) {
+        if !self.inner.borrow().variant.has_alarms() {
+            return;
+        }
+        let timer = self.alarm_timer.get();
+        unsafe {
+            ptimer_transaction_begin(timer);
+            ptimer_set_freq(timer, 1);
+            ptimer_set_limit(timer, 1, 1);
+            ptimer_run(timer, 0);
+            ptimer_transaction_commit(timer);
+        }
+    }
+
+    /// Assert `alarm_irq` when a latched alarm flag has its interrupt-enable
+    /// bit set. No-op on variants without alarms.
+    fn update_alarm_irq(&self) {
+        let inner = self.inner.borrow();
+        if !inner.variant.has_alarms() {
+            return;
+        }
+        let ctrl = inner.nvram[DS3231_CTRL_ADDR as usize];
+        let status = inner.nvram[DS3231_STATUS_ADDR as usize];
+        drop(inner);
+
+        let asserted = (status & STATUS_A1F != 0 && ctrl & DS3231_CTRL_A1IE != 0)
+            || (status & STATUS_A2F != 0 && ctrl & DS3231_CTRL_A2IE != 0);
+        unsafe { qemu_set_irq(self.alarm_irq.get(), asserted as i32) };
+    }
+
+    fn realize(&self) {
+        self.inner.borrow_mut().variant = RtcVariant::from_u8(self.variant_prop.get());
+
+        unsafe {
+            let bh = qemu_bh_new(Some(Self::sqw_tick_trampoline), self as *const Self as *mut c_void);
+            self.sqw_timer.set(ptimer_init(bh, PTIMER_POLICY_DEFAULT));
+
+            let dev = self.upcast::<DeviceState>().as_mut_ptr();
+            let mut pin: qemu_irq = std::ptr::null_mut();
+            qdev_init_gpio_out(dev, &mut pin, 1);
+            self.sqw.set(pin);
+
+            let mut alarm_pin: qemu_irq = std::ptr::null_mut();
+            qdev_init_gpio_out_named(dev, &mut alarm_pin, c"alarm-irq".as_ptr(), 1);
+            self.alarm_irq.set(alarm_pin);
+
+            let alarm_bh =
+                qemu_bh_new(Some(Self::alarm_tick_trampoline), self as *const Self as *mut c_void);
+            self.alarm_timer.set(ptimer_init(alarm_bh, PTIMER_POLICY_DEFAULT));
+        }
+
+        // Load any persisted state before deriving the SQW/alarm outputs
+        // from it, so a populated backing image takes effect immediately.
+        self.load_blk();
+        self.update_sqw(true);
+        self.update_alarm_irq();
+        self.start_alarm_timer();
+    }
+
+    /// Load the clock offset/wday_offset and NVRAM from the backing image,
+    /// if a `-drive` is attached. Without one, behavior is unchanged.
+    ///
+    /// Like pflash's backend-size validation, an attached image that isn't
+    /// exactly `BLK_IMAGE_SIZE` bytes, or a read that fails outright, is a
+    /// misconfiguration we'd rather fail loudly on than silently turn into
+    /// zeroed/garbage clock and NVRAM state.
+    fn load_blk(&self) {
+        let blk = self.blk.get();
+        if blk.is_null() {
+            return;
+        }
+
+        let len = unsafe { blk_getlength(blk) };
+        if len != BLK_IMAGE_SIZE as i64 {
+            panic!(
+                "ds1338: backing drive must be exactly {BLK_IMAGE_SIZE} bytes, got {len}"
+            );
+        }
+
+        let mut buf = [0u8; BLK_IMAGE_SIZE];
+        let ret = unsafe { blk_pread(blk, 0, buf.len() as i64, buf.as_mut_ptr() as *mut c_void, 0) };
+        if ret < 0 {
+            panic!("ds1338: failed to read backing drive (errno {ret})");
+        }
+
+        let mut inner = self.inner.borrow_mut();
+        inner.offset = i64::from_le_bytes(buf[0..8].try_into().unwrap());
+        inner.wday_offset = buf[8];
+        inner.nvram.copy_from_slice(&buf[9..9 + NVRAM_SIZE]);
+    }
+
+    /// Write the offset/wday_offset/NVRAM back to the backing image. No-op
+    /// without an attached `-drive`.
+    fn flush_blk(&self) {
+        let blk = self.blk.get();
+        if blk.is_null() {
+            return;
+        }
+
+        let inner = self.inner.borrow();
+        let mut buf = [0u8; BLK_IMAGE_SIZE];
+        buf[0..8].copy_from_slice(&inner.offset.to_le_bytes());
+        buf[8] = inner.wday_offset;
+        buf[9..9 + NVRAM_SIZE].copy_from_slice(&inner.nvram);
+        drop(inner);
+
+        let ret =
+            unsafe { blk_pwrite(blk, 0, buf.len() as i64, buf.as_ptr() as *const c_void, 0) };
+        if ret < 0 {
+            panic!("ds1338: failed to write backing drive (errno {ret})");
+        }
+    }
+
     fn event(&self, event: I2CEvent) -> I2CEvent {
         let mut inner = self.inner.borrow_mut();
+        let mut captured = false;
 
         match event {
             I2CEvent::START_RECV => {
                 inner.capture_current_time();
+                captured = true;
             }
             I2CEvent::START_SEND => {
                 inner.addr_byte = true;
             }
             _ => {}
         }
+        drop(inner);
+
+        if captured {
+            self.update_alarm_irq();
+        }
 
         I2CEvent::START_RECV
     }
 
-    fn reset_hold(&self, _reset_type: ResetType) {
+    fn reset_hold(&self, reset_type: ResetType) {
         let mut inner = self.inner.borrow_mut();
-        inner.offset = 0;
-        inner.wday_offset = 0;
+
+        // Only a cold/power-on reset loses the clock and NVRAM contents, the
+        // way a dead backup battery would; with a backing `-drive` attached
+        // they are kept as loaded from it at realize. A warm/soft reset
+        // leaves them alone and just resets the transient I2C protocol state.
+        if reset_type == ResetType::Cold && self.blk.get().is_null() {
+            let variant = inner.variant;
+            inner.offset = 0;
+            inner.wday_offset = 0;
+            inner.nvram.fill(0);
+            match variant.status_addr() {
+                Some(status_addr) => inner.nvram[status_addr as usize] |= STATUS_OSF,
+                None if variant == RtcVariant::Ds1338 => inner.nvram[7] |= CTRL_OSF,
+                None => {}
+            }
+        }
         inner.ptr = 0;
         inner.addr_byte = false;
-        inner.nvram.fill(0);
+        drop(inner);
+
+        self.update_sqw(true);
+        self.update_alarm_irq();
+        self.flush_blk();
     }
 }
 
@@ -258,12 +663,26 @@ impl ResettablePhasesImpl for DS1338State {
     const HOLD: Option<fn(&Self, ResetType)> = Some(Self::reset_hold);
 }
 
+impl DS1338State {
+    /// `sqw_timer`'s running countdown/phase is restored by `VMSTATE_PTIMER`
+    /// itself; only the (unmigrated) static pin level and alarm IRQ need
+    /// resyncing here, so the timer must not be reconfigured from scratch.
+    fn post_load(&self) -> i32 {
+        self.update_sqw(false);
+        self.update_alarm_irq();
+        0
+    }
+}
+
 pub const VMSTATE_DS1338: VMStateDescription<DS1338State> =
     VMStateDescriptionBuilder::<DS1338State>::new()
         .name(c"ds1338")
-        .version_id(2)
+        .version_id(4)
         .minimum_version_id(1)
+        .post_load(DS1338State::post_load)
         .fields(vmstate_fields! {
             migration::vmstate_of!(DS1338State, inner),
+            migration::vmstate_ptimer!(DS1338State, sqw_timer),
+            migration::vmstate_ptimer!(DS1338State, alarm_timer),
         })
         .build();